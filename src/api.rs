@@ -1,31 +1,15 @@
 use std::error::Error;
 
+use bitflags::bitflags;
 
-const RAX: u64 = 0;
-const RBX: u64 = 1;
-const RCX: u64 = 2;
-const RDX: u64 = 3;
-const RBP: u64 = 4;
-const RSI: u64 = 5;
-const RDP: u64 = 6;
-const RSP: u64 = 7;
-const RIP: u64 = 8;
-const RFLAGS: u64 = 9;
-const R8: u64 = 10;
-const R9: u64 = 11;
-const R10: u64 = 12;
-const R11: u64 = 13;
-const R12: u64 = 14;
-const R13: u64 = 15;
-const R14: u64 = 16;
-const R15: u64 = 17;
-const CR0: u64 = 18;
-const CR1: u64 = 19;
-const CR2: u64 = 20;
-const CR3: u64 = 21;
-
-
-
+bitflags! {
+    // guest physical page access permissions
+    pub struct Access: u8 {
+        const R = 0b001;
+        const W = 0b010;
+        const X = 0b100;
+    }
+}
 
 #[repr(C)]
 #[derive(Debug)]
@@ -114,17 +98,99 @@ pub trait Introspectable {
         unimplemented!();
     }
 
+    // write physical memory
+    fn write_physical(&mut self, _paddr: u64, _buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        unimplemented!();
+    }
+
     // get max physical address
     fn get_max_physical_addr(&self) -> Result<u64, Box<dyn Error>> {
         unimplemented!();
     }
 
+    // get access permissions on a given guest physical page
+    fn get_mem_access(&self, _gpa: u64) -> Result<Access, Box<dyn Error>> {
+        unimplemented!();
+    }
+
+    // set access permissions on a given guest physical page
+    fn set_mem_access(&mut self, _gpa: u64, _access: Access) -> Result<(), Box<dyn Error>> {
+        unimplemented!();
+    }
+
     fn read_registers(&self, _vcpu: u16) -> Result<Registers, Box<dyn Error>> {
         unimplemented!();
     }
 
-    fn write_registers(&self, _vcpu: u16, value: u64, reg: u64) -> Result<(), Box<dyn Error>> {
-	unimplemented!();
+    fn write_registers(&mut self, _vcpu: u16, _registers: Registers) -> Result<(), Box<dyn Error>> {
+        unimplemented!();
+    }
+
+    // translate a guest virtual address to a guest physical address by walking the guest's
+    // page tables (x86-64 only)
+    fn translate_v2p(&self, vcpu: u16, vaddr: u64) -> Result<Option<u64>, Box<dyn Error>> {
+        const PAGE_PRESENT: u64 = 1 << 0;
+        const PAGE_SIZE: u64 = 1 << 7;
+        const PAGE_ENTRY_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+        const CR4_LA57: u64 = 1 << 12;
+
+        let Registers::X86(regs) = self.read_registers(vcpu)?;
+
+        let read_entry = |table_base: u64, index: u64| -> Result<u64, Box<dyn Error>> {
+            let mut buf = [0u8; 8];
+            self.read_physical(table_base + index * 8, &mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        };
+
+        let pml4_index = (vaddr >> 39) & 0x1ff;
+        let pdpt_index = (vaddr >> 30) & 0x1ff;
+        let pd_index = (vaddr >> 21) & 0x1ff;
+        let pt_index = (vaddr >> 12) & 0x1ff;
+        let offset_1g = vaddr & 0x3fff_ffff;
+        let offset_2m = vaddr & 0x1f_ffff;
+        let offset_4k = vaddr & 0xfff;
+
+        let mut table_base = regs.cr3 & PAGE_ENTRY_ADDR_MASK;
+
+        // optional 5-level paging PML5 step
+        if regs.cr4 & CR4_LA57 != 0 {
+            let pml5_index = (vaddr >> 48) & 0x1ff;
+            let pml5_entry = read_entry(table_base, pml5_index)?;
+            if pml5_entry & PAGE_PRESENT == 0 {
+                return Ok(None);
+            }
+            table_base = pml5_entry & PAGE_ENTRY_ADDR_MASK;
+        }
+
+        let pml4_entry = read_entry(table_base, pml4_index)?;
+        if pml4_entry & PAGE_PRESENT == 0 {
+            return Ok(None);
+        }
+
+        let pdpt_entry = read_entry(pml4_entry & PAGE_ENTRY_ADDR_MASK, pdpt_index)?;
+        if pdpt_entry & PAGE_PRESENT == 0 {
+            return Ok(None);
+        }
+        if pdpt_entry & PAGE_SIZE != 0 {
+            // 1 GiB page
+            return Ok(Some((pdpt_entry & 0x000f_ffff_c000_0000) | offset_1g));
+        }
+
+        let pd_entry = read_entry(pdpt_entry & PAGE_ENTRY_ADDR_MASK, pd_index)?;
+        if pd_entry & PAGE_PRESENT == 0 {
+            return Ok(None);
+        }
+        if pd_entry & PAGE_SIZE != 0 {
+            // 2 MiB page
+            return Ok(Some((pd_entry & 0x000f_ffff_ffe0_0000) | offset_2m));
+        }
+
+        let pt_entry = read_entry(pd_entry & PAGE_ENTRY_ADDR_MASK, pt_index)?;
+        if pt_entry & PAGE_PRESENT == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some((pt_entry & PAGE_ENTRY_ADDR_MASK) | offset_4k))
     }
 
     // pause the VM
@@ -170,12 +236,18 @@ pub trait Introspectable {
 #[derive(Debug, Copy, Clone)]
 pub enum InterceptType {
     Cr(CrType),
+    Msr(u32),
+    Pagefault,
+    Breakpoint,
 }
 
 #[repr(C)]
 #[derive(Debug)]
 pub enum EventType {
     Cr { cr_type: CrType, new: u64, old: u64 },
+    Msr { msr: u32, new: u64, old: u64 },
+    Pagefault { gva: u64, gpa: u64, access: Access },
+    Breakpoint { gpa: u64, insn_len: u8 },
 }
 
 #[repr(C)]
@@ -197,4 +269,8 @@ pub struct Event {
 #[derive(Debug)]
 pub enum EventReplyType {
     Continue,
+    // re-execute the original instruction instead of the one injected by the introspector
+    Retry,
+    // write back the given registers before resuming the VCPU
+    SetRegisters(Registers),
 }
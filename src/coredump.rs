@@ -0,0 +1,290 @@
+//! Generate ELF64 `ET_CORE` guest memory dumps, following the approach used by
+//! cloud-hypervisor's `coredump` module: one `PT_LOAD` segment per contiguous guest RAM range,
+//! plus a `PT_NOTE` segment carrying each VCPU's GP/segment state as an `NT_PRSTATUS` note and
+//! its control registers (CR0/CR3/CR4) as a libmicrovmi-specific note.
+//!
+//! Known limitation: `Introspectable` has no way to enumerate guest RAM ranges today, so this
+//! module emits a single `PT_LOAD` spanning `[0, get_max_physical_addr())` instead of one per
+//! actual RAM range. On real guests that span includes MMIO holes (e.g. the sub-4GiB PCI hole),
+//! whose contents will show up as whatever `read_physical` returns for them rather than being
+//! excluded from the dump.
+
+use std::error::Error;
+use std::io::Write;
+use std::mem;
+
+use crate::api::{Introspectable, Registers};
+
+const EI_NIDENT: usize = 16;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_R: u32 = 4;
+const PF_W: u32 = 2;
+const PF_X: u32 = 1;
+const NT_PRSTATUS: u32 = 1;
+// there is no standard ELF core note for x86 control registers (NT_PRSTATUS only carries GP,
+// segment and eflags state), so CR0/CR3/CR4 ride along in a libmicrovmi-specific note instead of
+// being silently dropped
+const NT_MICROVMI_CRREGS: u32 = 0x4d56_4352; // "MVCR"
+
+// chunk physical memory reads so a single dump doesn't require one giant allocation
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+#[repr(C)]
+struct Elf64Ehdr {
+    e_ident: [u8; EI_NIDENT],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[repr(C)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+// minimal x86-64 NT_PRSTATUS payload: pid/signal bookkeeping zeroed, followed by the GP/segment
+// registers in the kernel's `user_regs_struct` order
+#[repr(C)]
+struct PrStatus {
+    pad_before_regs: [u8; 112],
+    regs: UserRegsStruct,
+    pad_after_regs: [u8; 8],
+}
+
+#[repr(C)]
+struct UserRegsStruct {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    orig_rax: u64,
+    rip: u64,
+    cs: u64,
+    eflags: u64,
+    rsp: u64,
+    ss: u64,
+    fs_base: u64,
+    gs_base: u64,
+    ds: u64,
+    es: u64,
+    fs: u64,
+    gs: u64,
+}
+
+// libmicrovmi-specific note payload carrying the control registers that NT_PRSTATUS has no room
+// for
+#[repr(C)]
+struct CrRegs {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts((value as *const T) as *const u8, mem::size_of::<T>()) }
+}
+
+// `name` is the NUL-terminated note name, already padded with trailing NULs to a 4-byte boundary;
+// `n_namesz` only covers the unpadded "name\0" per the ELF note spec
+fn elf_note(n_namesz: u32, name: &[u8], n_type: u32, desc: &[u8]) -> Vec<u8> {
+    let nhdr = Elf64Nhdr {
+        n_namesz,
+        n_descsz: desc.len() as u32,
+        n_type,
+    };
+    let mut note = Vec::new();
+    note.extend_from_slice(as_bytes(&nhdr));
+    note.extend_from_slice(name);
+    note.extend_from_slice(desc);
+    note
+}
+
+fn prstatus_note(registers: &Registers) -> Vec<u8> {
+    let Registers::X86(x86) = registers;
+    let regs = UserRegsStruct {
+        r15: x86.r15,
+        r14: x86.r14,
+        r13: x86.r13,
+        r12: x86.r12,
+        rbp: x86.rbp,
+        rbx: x86.rbx,
+        r11: x86.r11,
+        r10: x86.r10,
+        r9: x86.r9,
+        r8: x86.r8,
+        rax: x86.rax,
+        rcx: x86.rcx,
+        rdx: x86.rdx,
+        rsi: x86.rsi,
+        rdi: x86.rdi,
+        orig_rax: x86.rax,
+        rip: x86.rip,
+        cs: x86.cs.selector as u64,
+        eflags: x86.rflags,
+        rsp: x86.rsp,
+        ss: x86.ss.selector as u64,
+        fs_base: x86.fs.base,
+        gs_base: x86.gs.base,
+        ds: x86.ds.selector as u64,
+        es: x86.es.selector as u64,
+        fs: x86.fs.selector as u64,
+        gs: x86.gs.selector as u64,
+    };
+    let prstatus = PrStatus {
+        pad_before_regs: [0; 112],
+        regs,
+        pad_after_regs: [0; 8],
+    };
+
+    elf_note(5, b"CORE\0\0\0\0", NT_PRSTATUS, as_bytes(&prstatus)) // "CORE" + NUL, padded to 8 bytes
+}
+
+fn crregs_note(registers: &Registers) -> Vec<u8> {
+    let Registers::X86(x86) = registers;
+    let cr_regs = CrRegs {
+        cr0: x86.cr0,
+        cr3: x86.cr3,
+        cr4: x86.cr4,
+    };
+    elf_note(12, b"LIBMICROVMI\0", NT_MICROVMI_CRREGS, as_bytes(&cr_regs)) // "LIBMICROVMI" + NUL, already 4-byte aligned
+}
+
+// every note for a single VCPU: GP/segment state plus the libmicrovmi control-register extension
+fn vcpu_notes(registers: &Registers) -> Vec<u8> {
+    let mut notes = prstatus_note(registers);
+    notes.extend_from_slice(&crregs_note(registers));
+    notes
+}
+
+/// Pause the domain, then emit an ELF64 `ET_CORE` snapshot of its physical memory and VCPU
+/// register state to `writer`.
+pub fn dump_core(vmi: &mut dyn Introspectable, writer: impl Write) -> Result<(), Box<dyn Error>> {
+    vmi.pause()?;
+    // whatever happens while dumping, make sure the guest is left running rather than stuck
+    // paused on an I/O error or a dead VCPU
+    let result = dump_paused(vmi, writer);
+    vmi.resume()?;
+    result
+}
+
+fn dump_paused(vmi: &mut dyn Introspectable, mut writer: impl Write) -> Result<(), Box<dyn Error>> {
+    let max_addr = vmi.get_max_physical_addr()?;
+    let vcpu_count = vmi.get_vcpu_count()?;
+
+    let mut notes = Vec::new();
+    for vcpu in 0..vcpu_count {
+        let registers = vmi.read_registers(vcpu)?;
+        notes.extend_from_slice(&vcpu_notes(&registers));
+    }
+
+    let ehdr_size = mem::size_of::<Elf64Ehdr>() as u64;
+    let phdr_size = mem::size_of::<Elf64Phdr>() as u64;
+    // one PT_NOTE, plus a single PT_LOAD covering [0, max_addr) rather than one per RAM range
+    // (see the module-level doc comment for why)
+    let phnum: u16 = 2;
+    let phoff = ehdr_size;
+    let notes_offset = phoff + phnum as u64 * phdr_size;
+    let mem_offset = notes_offset + notes.len() as u64;
+
+    let mut e_ident = [0u8; EI_NIDENT];
+    e_ident[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    e_ident[4] = ELFCLASS64;
+    e_ident[5] = ELFDATA2LSB;
+    e_ident[6] = EV_CURRENT;
+
+    let ehdr = Elf64Ehdr {
+        e_ident,
+        e_type: ET_CORE,
+        e_machine: EM_X86_64,
+        e_version: EV_CURRENT as u32,
+        e_entry: 0,
+        e_phoff: phoff,
+        e_shoff: 0,
+        e_flags: 0,
+        e_ehsize: ehdr_size as u16,
+        e_phentsize: phdr_size as u16,
+        e_phnum: phnum,
+        e_shentsize: 0,
+        e_shnum: 0,
+        e_shstrndx: 0,
+    };
+
+    let note_phdr = Elf64Phdr {
+        p_type: PT_NOTE,
+        p_flags: 0,
+        p_offset: notes_offset,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: notes.len() as u64,
+        p_memsz: notes.len() as u64,
+        p_align: 4,
+    };
+
+    let load_phdr = Elf64Phdr {
+        p_type: PT_LOAD,
+        p_flags: PF_R | PF_W | PF_X,
+        p_offset: mem_offset,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: max_addr,
+        p_memsz: max_addr,
+        p_align: 0x1000,
+    };
+
+    writer.write_all(as_bytes(&ehdr))?;
+    writer.write_all(as_bytes(&note_phdr))?;
+    writer.write_all(as_bytes(&load_phdr))?;
+    writer.write_all(&notes)?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut paddr = 0u64;
+    while paddr < max_addr {
+        let chunk_len = std::cmp::min(CHUNK_SIZE, max_addr - paddr) as usize;
+        vmi.read_physical(paddr, &mut buf[..chunk_len])?;
+        writer.write_all(&buf[..chunk_len])?;
+        paddr += chunk_len as u64;
+    }
+
+    Ok(())
+}
@@ -4,11 +4,12 @@ use std::mem;
 use std::vec::Vec;
 
 use kvmi::{
-    KVMIntrospectable, KVMiCr, KVMiEvent, KVMiEventReply, KVMiEventType, KVMiInterceptType,
+    kvm_regs, KVMIntrospectable, KVMiCr, KVMiEvent, KVMiEventReply, KVMiEventType,
+    KVMiInterceptType,
 };
 
 use crate::api::{
-    CrType, Event, EventReplyType, EventType, InterceptType, Introspectable, Registers,
+    Access, CrType, Event, EventReplyType, EventType, InterceptType, Introspectable, Registers,
     X86Registers,
 };
 
@@ -57,11 +58,23 @@ impl<T: KVMIntrospectable> Introspectable for Kvm<T> {
         Ok(self.kvmi.read_physical(paddr, buf)?)
     }
 
+    fn write_physical(&mut self, paddr: u64, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(self.kvmi.write_physical(paddr, buf)?)
+    }
+
     fn get_max_physical_addr(&self) -> Result<u64, Box<dyn Error>> {
-        // No API in KVMi at the moment
-        // fake 512MB
-        let max_addr = 1024 * 1024 * 512;
-        Ok(max_addr)
+        const PAGE_SHIFT: u64 = 12;
+        let max_gfn = self.kvmi.get_maximum_gfn()?;
+        Ok(max_gfn << PAGE_SHIFT)
+    }
+
+    fn get_mem_access(&self, gpa: u64) -> Result<Access, Box<dyn Error>> {
+        let access = self.kvmi.get_page_access(gpa)?;
+        Ok(Access::from_bits_truncate(access))
+    }
+
+    fn set_mem_access(&mut self, gpa: u64, access: Access) -> Result<(), Box<dyn Error>> {
+        Ok(self.kvmi.set_page_access(gpa, access.bits())?)
     }
 
     fn read_registers(&self, vcpu: u16) -> Result<Registers, Box<dyn Error>> {
@@ -93,6 +106,31 @@ impl<T: KVMIntrospectable> Introspectable for Kvm<T> {
         }))
     }
 
+    fn write_registers(&mut self, vcpu: u16, registers: Registers) -> Result<(), Box<dyn Error>> {
+        let Registers::X86(x86_registers) = registers;
+        let regs = kvm_regs {
+            rax: x86_registers.rax,
+            rbx: x86_registers.rbx,
+            rcx: x86_registers.rcx,
+            rdx: x86_registers.rdx,
+            rsi: x86_registers.rsi,
+            rdi: x86_registers.rdi,
+            rsp: x86_registers.rsp,
+            rbp: x86_registers.rbp,
+            r8: x86_registers.r8,
+            r9: x86_registers.r9,
+            r10: x86_registers.r10,
+            r11: x86_registers.r11,
+            r12: x86_registers.r12,
+            r13: x86_registers.r13,
+            r14: x86_registers.r14,
+            r15: x86_registers.r15,
+            rip: x86_registers.rip,
+            rflags: x86_registers.rflags,
+        };
+        Ok(self.kvmi.set_registers(vcpu, &regs)?)
+    }
+
     fn pause(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("pause");
         // already paused ?
@@ -146,6 +184,13 @@ impl<T: KVMIntrospectable> Introspectable for Kvm<T> {
                 };
                 Ok(self.kvmi.control_cr(vcpu, kvmi_cr, enabled)?)
             }
+            InterceptType::Msr(msr) => Ok(self.kvmi.control_msr(vcpu, msr, enabled)?),
+            InterceptType::Pagefault => Ok(self
+                .kvmi
+                .control_events(vcpu, KVMiInterceptType::Pagefault, enabled)?),
+            InterceptType::Breakpoint => Ok(self
+                .kvmi
+                .control_events(vcpu, KVMiInterceptType::Breakpoint, enabled)?),
         }
     }
 
@@ -166,6 +211,15 @@ impl<T: KVMIntrospectable> Introspectable for Kvm<T> {
                         new,
                         old,
                     },
+                    KVMiEventType::Msr { msr, new, old } => EventType::Msr { msr, new, old },
+                    KVMiEventType::PF { gva, gpa, access } => EventType::Pagefault {
+                        gva,
+                        gpa,
+                        access: Access::from_bits_truncate(access),
+                    },
+                    KVMiEventType::Breakpoint { gpa, insn_len } => {
+                        EventType::Breakpoint { gpa, insn_len }
+                    }
                     KVMiEventType::PauseVCPU => panic!("Unexpected PauseVCPU event. It should have been popped by resume VM. (Did you forget to resume your VM ?)"),
                     _ => unimplemented!()
                 };
@@ -189,6 +243,12 @@ impl<T: KVMIntrospectable> Introspectable for Kvm<T> {
     ) -> Result<(), Box<dyn Error>> {
         let kvm_reply_type = match reply_type {
             EventReplyType::Continue => KVMiEventReply::Continue,
+            EventReplyType::Retry => KVMiEventReply::Retry,
+            EventReplyType::SetRegisters(registers) => {
+                // write back registers before resuming the VCPU
+                self.write_registers(event.vcpu, registers)?;
+                KVMiEventReply::Continue
+            }
         };
         // get KVMiEvent associated with this VCPU
         let vcpu_index: usize = event.vcpu.try_into().unwrap();
@@ -200,11 +260,19 @@ impl<T: KVMIntrospectable> Introspectable for Kvm<T> {
 impl<T: KVMIntrospectable> Drop for Kvm<T> {
     fn drop(&mut self) {
         debug!("KVM driver close");
-        // disable all control register interception
         for vcpu in 0..self.get_vcpu_count().unwrap() {
+            // disable all control register interception
             self.kvmi
                 .control_events(vcpu, KVMiInterceptType::Cr, false)
                 .unwrap();
+            // disable page fault interception
+            self.kvmi
+                .control_events(vcpu, KVMiInterceptType::Pagefault, false)
+                .unwrap();
+            // disable breakpoint interception
+            self.kvmi
+                .control_events(vcpu, KVMiInterceptType::Breakpoint, false)
+                .unwrap();
         }
     }
 }
@@ -261,6 +329,24 @@ mod tests {
                 )
                 .times(1)
                 .returning(|_, _, _| Ok(()));
+            kvmi_mock
+                .expect_control_events()
+                .with(
+                    eq(vcpu as u16),
+                    function(|x| matches!(x, KVMiInterceptType::Pagefault)),
+                    eq(false),
+                )
+                .times(1)
+                .returning(|_, _, _| Ok(()));
+            kvmi_mock
+                .expect_control_events()
+                .with(
+                    eq(vcpu as u16),
+                    function(|x| matches!(x, KVMiInterceptType::Breakpoint)),
+                    eq(false),
+                )
+                .times(1)
+                .returning(|_, _, _| Ok(()));
         }
 
         let result = Kvm::new("some_vm", kvmi_mock);
@@ -268,6 +354,261 @@ mod tests {
         assert!(result.is_ok(), "Expected ok, got error instead!");
     }
 
+    #[test]
+    fn test_get_max_physical_addr_shifts_maximum_gfn_by_page_size() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock
+            .expect_get_maximum_gfn()
+            .returning(|| Ok(0x20000));
+
+        let kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        assert_eq!(kvm.get_max_physical_addr().unwrap(), 0x20000000);
+    }
+
+    #[test]
+    fn test_get_mem_access_decodes_page_access_bits() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock
+            .expect_get_page_access()
+            .with(eq(0x1000))
+            .returning(|_| Ok(0b011)); // R | W
+
+        let kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        assert_eq!(kvm.get_mem_access(0x1000).unwrap(), Access::R | Access::W);
+    }
+
+    #[test]
+    fn test_set_mem_access_forwards_access_bits() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock
+            .expect_set_page_access()
+            .with(eq(0x1000), eq(Access::X.bits()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        kvm.set_mem_access(0x1000, Access::X).unwrap();
+    }
+
+    #[test]
+    fn test_toggle_intercept_pagefault_calls_control_events() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .with(
+                eq(0u16),
+                function(|x| matches!(x, KVMiInterceptType::Pagefault)),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        kvm.toggle_intercept(0, InterceptType::Pagefault, true)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_listen_translates_pf_event_into_pagefault_event() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock.expect_wait_and_pop_event().returning(|_| {
+            Ok(Some(KVMiEvent {
+                vcpu: 0,
+                ev_type: KVMiEventType::PF {
+                    gva: 0x4000,
+                    gpa: 0x5000,
+                    access: 0b001, // R
+                },
+            }))
+        });
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        let event = kvm.listen(0).unwrap().unwrap();
+        assert_eq!(event.vcpu, 0);
+        match event.kind {
+            EventType::Pagefault { gva, gpa, access } => {
+                assert_eq!(gva, 0x4000);
+                assert_eq!(gpa, 0x5000);
+                assert_eq!(access, Access::R);
+            }
+            other => panic!("expected EventType::Pagefault, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_toggle_intercept_breakpoint_calls_control_events() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .with(
+                eq(0u16),
+                function(|x| matches!(x, KVMiInterceptType::Breakpoint)),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        kvm.toggle_intercept(0, InterceptType::Breakpoint, true)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_listen_translates_breakpoint_event() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock.expect_wait_and_pop_event().returning(|_| {
+            Ok(Some(KVMiEvent {
+                vcpu: 0,
+                ev_type: KVMiEventType::Breakpoint {
+                    gpa: 0x1000,
+                    insn_len: 1,
+                },
+            }))
+        });
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+
+        let event = kvm.listen(0).unwrap().unwrap();
+        assert_eq!(event.vcpu, 0);
+        match event.kind {
+            EventType::Breakpoint { gpa, insn_len } => {
+                assert_eq!(gpa, 0x1000);
+                assert_eq!(insn_len, 1);
+            }
+            other => panic!("expected EventType::Breakpoint, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reply_event_retry_maps_to_kvmi_retry() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock.expect_wait_and_pop_event().returning(|_| {
+            Ok(Some(KVMiEvent {
+                vcpu: 0,
+                ev_type: KVMiEventType::Breakpoint {
+                    gpa: 0x1000,
+                    insn_len: 1,
+                },
+            }))
+        });
+        kvmi_mock
+            .expect_reply()
+            .withf(|_, reply_type| matches!(reply_type, KVMiEventReply::Retry))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+        let event = kvm.listen(0).unwrap().unwrap();
+
+        kvm.reply_event(event, EventReplyType::Retry).unwrap();
+    }
+
+    #[test]
+    fn test_reply_event_set_registers_writes_back_registers_then_continues() {
+        let mut kvmi_mock = MockKVMi::default();
+        kvmi_mock.expect_init().returning(|_| Ok(()));
+        kvmi_mock.expect_get_vcpu_count().returning(|| Ok(1));
+        kvmi_mock
+            .expect_control_events()
+            .returning(|_, _, _| Ok(()));
+        kvmi_mock.expect_wait_and_pop_event().returning(|_| {
+            Ok(Some(KVMiEvent {
+                vcpu: 0,
+                ev_type: KVMiEventType::Breakpoint {
+                    gpa: 0x1000,
+                    insn_len: 1,
+                },
+            }))
+        });
+        kvmi_mock
+            .expect_set_registers()
+            .withf(|_, regs| regs.rip == 0x4242)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        kvmi_mock
+            .expect_reply()
+            .withf(|_, reply_type| matches!(reply_type, KVMiEventReply::Continue))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut kvm = Kvm::new("some_vm", kvmi_mock).unwrap();
+        let event = kvm.listen(0).unwrap().unwrap();
+
+        let registers = Registers::X86(X86Registers {
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: 0,
+            rsi: 0,
+            rdi: 0,
+            rsp: 0,
+            rbp: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip: 0x4242,
+            rflags: 0,
+            cr0: 0,
+            cr3: 0,
+            cr4: 0,
+            fs_base: 0,
+        });
+
+        kvm.reply_event(event, EventReplyType::SetRegisters(registers))
+            .unwrap();
+    }
+
     mock! {
         KVMi{}
         trait Debug {
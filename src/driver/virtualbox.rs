@@ -28,6 +28,10 @@ impl Introspectable for VBox {
         self.fdp.read_physical_memory(paddr, buf)
     }
 
+    fn write_physical(&mut self, paddr: u64, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.fdp.write_physical_memory(paddr, buf)
+    }
+
     fn get_max_physical_addr(&self) -> Result<u64, Box<dyn Error>> {
         self.fdp.get_physical_memory_size()
     }
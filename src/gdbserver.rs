@@ -0,0 +1,279 @@
+//! GDB Remote Serial Protocol server, wrapping any `Introspectable` driver so that a standard
+//! `gdb` (or IDA) can attach to a live VM for interactive introspection without an in-guest agent.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::TcpListener;
+
+use gdbstub::common::Signal;
+use gdbstub::conn::ConnectionExt;
+use gdbstub::stub::{DisconnectReason, GdbStub, SingleThreadStopReason};
+use gdbstub::target::ext::base::singlethread::{SingleThreadBase, SingleThreadResume};
+use gdbstub::target::ext::breakpoints::{Breakpoints, SwBreakpoint};
+use gdbstub::target::{Target, TargetError, TargetResult};
+use gdbstub_arch::x86::reg::X86_64CoreRegs;
+use gdbstub_arch::x86::X86_64_SSE;
+
+use crate::api::{EventReplyType, EventType, InterceptType, Introspectable, Registers};
+
+// vcpu 0 only, for now: gdbstub's `SingleThreadBase` targets a single execution context
+const VCPU: u16 = 0;
+
+const INT3: u8 = 0xcc;
+
+pub struct GdbServer {
+    vmi: Box<dyn Introspectable>,
+    // gpa -> original byte, for every software breakpoint currently injected
+    sw_breakpoints: BTreeMap<u64, u8>,
+}
+
+impl GdbServer {
+    pub fn new(vmi: Box<dyn Introspectable>) -> Self {
+        GdbServer {
+            vmi,
+            sw_breakpoints: BTreeMap::new(),
+        }
+    }
+
+    // serve a single GDB session over `listener`, blocking until the client detaches
+    pub fn serve(mut self, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+        let (stream, _addr) = listener.accept()?;
+        let connection: Box<dyn ConnectionExt<Error = std::io::Error>> = Box::new(stream);
+        let gdb = GdbStub::new(connection);
+
+        let run_result = gdb.run_blocking::<GdbBlockingEventLoop>(&mut self);
+        // whatever reason the session ended for, don't leave injected 0xCC's (or the breakpoint
+        // intercept) behind for a guest that no longer has a listener attached
+        self.clear_sw_breakpoints()?;
+        let disconnect_reason = run_result?;
+        match disconnect_reason {
+            DisconnectReason::Disconnect => self.vmi.resume()?,
+            DisconnectReason::TargetExited(_) | DisconnectReason::TargetTerminated(_) => {}
+            DisconnectReason::Kill => self.vmi.resume()?,
+        }
+        Ok(())
+    }
+
+    fn v2p(&self, vaddr: u64) -> Result<u64, Box<dyn Error>> {
+        self.vmi
+            .translate_v2p(VCPU, vaddr)?
+            .ok_or_else(|| "unmapped guest virtual address".into())
+    }
+
+    // restore every injected 0xCC and disable the breakpoint intercept
+    fn clear_sw_breakpoints(&mut self) -> Result<(), Box<dyn Error>> {
+        for (gpa, original) in std::mem::take(&mut self.sw_breakpoints) {
+            self.vmi.write_physical(gpa, &[original])?;
+        }
+        self.vmi
+            .toggle_intercept(VCPU, InterceptType::Breakpoint, false)
+    }
+}
+
+impl Target for GdbServer {
+    type Arch = X86_64_SSE;
+    type Error = Box<dyn Error>;
+
+    #[inline(always)]
+    fn base_ops(&mut self) -> gdbstub::target::ext::base::BaseOps<Self::Arch, Self::Error> {
+        gdbstub::target::ext::base::BaseOps::SingleThread(self)
+    }
+
+    #[inline(always)]
+    fn support_breakpoints(
+        &mut self,
+    ) -> Option<gdbstub::target::ext::breakpoints::BreakpointsOps<Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GdbServer {
+    fn read_registers(&mut self, regs: &mut X86_64CoreRegs) -> TargetResult<(), Self> {
+        let Registers::X86(x86) = self
+            .vmi
+            .read_registers(VCPU)
+            .map_err(|_| TargetError::NonFatal)?;
+        regs.regs = [
+            x86.rax, x86.rbx, x86.rcx, x86.rdx, x86.rsi, x86.rdi, x86.rbp, x86.rsp, x86.r8,
+            x86.r9, x86.r10, x86.r11, x86.r12, x86.r13, x86.r14, x86.r15,
+        ];
+        regs.rip = x86.rip;
+        regs.eflags = x86.rflags as u32;
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &X86_64CoreRegs) -> TargetResult<(), Self> {
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15] =
+            regs.regs;
+        let current = self
+            .vmi
+            .read_registers(VCPU)
+            .map_err(|_| TargetError::NonFatal)?;
+        let Registers::X86(mut x86) = current;
+        x86.rax = rax;
+        x86.rbx = rbx;
+        x86.rcx = rcx;
+        x86.rdx = rdx;
+        x86.rsi = rsi;
+        x86.rdi = rdi;
+        x86.rbp = rbp;
+        x86.rsp = rsp;
+        x86.r8 = r8;
+        x86.r9 = r9;
+        x86.r10 = r10;
+        x86.r11 = r11;
+        x86.r12 = r12;
+        x86.r13 = r13;
+        x86.r14 = r14;
+        x86.r15 = r15;
+        x86.rip = regs.rip;
+        x86.rflags = regs.eflags as u64;
+        self.vmi
+            .write_registers(VCPU, Registers::X86(x86))
+            .map_err(|_| TargetError::NonFatal)
+    }
+
+    fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let paddr = self.v2p(start_addr).map_err(|_| TargetError::NonFatal)?;
+        self.vmi
+            .read_physical(paddr, data)
+            .map_err(|_| TargetError::NonFatal)?;
+        Ok(data.len())
+    }
+
+    fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
+        let paddr = self.v2p(start_addr).map_err(|_| TargetError::NonFatal)?;
+        self.vmi
+            .write_physical(paddr, data)
+            .map_err(|_| TargetError::NonFatal)
+    }
+
+    #[inline(always)]
+    fn support_resume(
+        &mut self,
+    ) -> Option<gdbstub::target::ext::base::singlethread::SingleThreadResumeOps<Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadResume for GdbServer {
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.vmi.resume()
+    }
+}
+
+impl Breakpoints for GdbServer {
+    #[inline(always)]
+    fn support_sw_breakpoint(
+        &mut self,
+    ) -> Option<gdbstub::target::ext::breakpoints::SwBreakpointOps<Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbServer {
+    fn add_sw_breakpoint(&mut self, addr: u64, _kind: usize) -> TargetResult<bool, Self> {
+        let gpa = self.v2p(addr).map_err(|_| TargetError::NonFatal)?;
+        if self.sw_breakpoints.contains_key(&gpa) {
+            return Ok(true);
+        }
+
+        // enable the breakpoint intercept before injecting the first 0xCC, so the #BP is
+        // actually trapped
+        if self.sw_breakpoints.is_empty() {
+            self.vmi
+                .toggle_intercept(VCPU, InterceptType::Breakpoint, true)
+                .map_err(|_| TargetError::NonFatal)?;
+        }
+
+        let mut original = [0u8; 1];
+        self.vmi
+            .read_physical(gpa, &mut original)
+            .map_err(|_| TargetError::NonFatal)?;
+        self.vmi
+            .write_physical(gpa, &[INT3])
+            .map_err(|_| TargetError::NonFatal)?;
+        self.sw_breakpoints.insert(gpa, original[0]);
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u64, _kind: usize) -> TargetResult<bool, Self> {
+        let gpa = self.v2p(addr).map_err(|_| TargetError::NonFatal)?;
+        let original = match self.sw_breakpoints.remove(&gpa) {
+            Some(original) => original,
+            None => return Ok(false),
+        };
+
+        self.vmi
+            .write_physical(gpa, &[original])
+            .map_err(|_| TargetError::NonFatal)?;
+
+        // last breakpoint removed: stop trapping #BP altogether
+        if self.sw_breakpoints.is_empty() {
+            self.vmi
+                .toggle_intercept(VCPU, InterceptType::Breakpoint, false)
+                .map_err(|_| TargetError::NonFatal)?;
+        }
+        Ok(true)
+    }
+}
+
+enum GdbBlockingEventLoop {}
+
+impl gdbstub::stub::run_blocking::BlockingEventLoop for GdbBlockingEventLoop {
+    type Target = GdbServer;
+    type Connection = Box<dyn ConnectionExt<Error = std::io::Error>>;
+    type StopReason = SingleThreadStopReason<u64>;
+
+    fn wait_for_stop_reason(
+        target: &mut GdbServer,
+        conn: &mut Self::Connection,
+    ) -> Result<
+        gdbstub::stub::run_blocking::Event<Self::StopReason>,
+        gdbstub::stub::run_blocking::WaitForStopReasonError<
+            <Self::Target as Target>::Error,
+            <Self::Connection as gdbstub::conn::Connection>::Error,
+        >,
+    > {
+        target.vmi.resume().map_err(|e| {
+            gdbstub::stub::run_blocking::WaitForStopReasonError::Target(e)
+        })?;
+
+        loop {
+            if conn.peek().map(|b| b.is_some()).unwrap_or(false) {
+                let byte = conn
+                    .read()
+                    .map_err(gdbstub::stub::run_blocking::WaitForStopReasonError::Connection)?;
+                return Ok(gdbstub::stub::run_blocking::Event::IncomingData(byte));
+            }
+
+            if let Some(event) = target
+                .vmi
+                .listen(100)
+                .map_err(gdbstub::stub::run_blocking::WaitForStopReasonError::Target)?
+            {
+                // only a software breakpoint should be reported to GDB as a stop; any other
+                // intercepted event (e.g. a routine CR write) is just continued transparently
+                let is_breakpoint = matches!(event.kind, EventType::Breakpoint { .. });
+                target
+                    .vmi
+                    .reply_event(event, EventReplyType::Continue)
+                    .map_err(gdbstub::stub::run_blocking::WaitForStopReasonError::Target)?;
+
+                if !is_breakpoint {
+                    continue;
+                }
+                return Ok(gdbstub::stub::run_blocking::Event::TargetStopped(
+                    SingleThreadStopReason::SwBreak(()),
+                ));
+            }
+        }
+    }
+
+    fn on_interrupt(
+        target: &mut GdbServer,
+    ) -> Result<Option<Self::StopReason>, <Self::Target as Target>::Error> {
+        target.vmi.pause()?;
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
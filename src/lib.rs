@@ -1,5 +1,8 @@
 pub mod api;
+pub mod coredump;
 mod driver;
+#[cfg(feature = "gdbserver")]
+pub mod gdbserver;
 
 use api::Introspectable;
 use api::DriverType;